@@ -6,17 +6,92 @@ use layout::box::{RenderBox};
 use layout::context::LayoutContext;
 use layout::display_list_builder::{DisplayListBuilder, ExtraDisplayListData};
 use layout::display_list_builder::{FlowDisplayListBuilderMethods};
-use layout::flow::{FloatFlow, FlowData};
-use layout::model::{MaybeAuto};
-use layout::float_context::{FloatContext, PlacementInfo, FloatLeft};
+use layout::flow::{FlowContext, FlowData};
+use layout::model::{MaybeAuto, Auto, Specified};
+use layout::float_context::{FloatContext, PlacementInfo, FloatType, FloatLeft, FloatRight, Invalid};
 
 use core::cell::Cell;
 use geom::point::Point2D;
 use geom::rect::Rect;
+use geom::size::Size2D;
 use gfx::display_list::DisplayList;
 use gfx::geometry::Au;
 use gfx::geometry;
 use servo_util::tree::{TreeNodeRef, TreeUtils};
+use servo_util::writing_mode::WritingMode;
+
+/// Writes the logical inline-start position onto the physical `x`/`y` of
+/// `rect` according to `wm`.
+fn set_inline_position(wm: WritingMode, rect: &mut Rect<Au>, pos: Au) {
+    if wm.is_vertical() {
+        rect.origin.y = pos;
+    } else {
+        rect.origin.x = pos;
+    }
+}
+
+/// Writes the logical block-start position onto the physical `x`/`y` of
+/// `rect` according to `wm`.
+fn set_block_position(wm: WritingMode, rect: &mut Rect<Au>, pos: Au) {
+    if wm.is_vertical() {
+        rect.origin.x = pos;
+    } else {
+        rect.origin.y = pos;
+    }
+}
+
+/// Writes the logical inline-size onto the physical `width`/`height` of
+/// `rect` according to `wm`.
+fn set_inline_size(wm: WritingMode, rect: &mut Rect<Au>, size: Au) {
+    if wm.is_vertical() {
+        rect.size.height = size;
+    } else {
+        rect.size.width = size;
+    }
+}
+
+/// Writes the logical block-size onto the physical `width`/`height` of
+/// `rect` according to `wm`.
+fn set_block_size(wm: WritingMode, rect: &mut Rect<Au>, size: Au) {
+    if wm.is_vertical() {
+        rect.size.width = size;
+    } else {
+        rect.size.height = size;
+    }
+}
+
+/// Reads the logical inline-size from the physical `width`/`height` of `rect`
+/// according to `wm`.
+fn inline_size(wm: WritingMode, rect: &Rect<Au>) -> Au {
+    if wm.is_vertical() {
+        rect.size.height
+    } else {
+        rect.size.width
+    }
+}
+
+/// Collapses a block-size property resolved against an *indefinite* containing
+/// block. A float is not threaded a definite containing-block block-size in
+/// this module, so percentages cannot be resolved; per CSS 10.5 they then
+/// compute to `auto`. The two arguments are the same property resolved against
+/// two different bases: if the result varies the property was a percentage (so
+/// `auto`), otherwise it is a definite length (or already `auto`) and is kept.
+fn block_prop_or_auto(small_base: MaybeAuto, large_base: MaybeAuto) -> MaybeAuto {
+    match (small_base, large_base) {
+        (Specified(a), Specified(b)) if a != b => Auto,
+        (resolved, _) => resolved,
+    }
+}
+
+/// Reads the logical block-size from the physical `width`/`height` of `rect`
+/// according to `wm`.
+fn block_size(wm: WritingMode, rect: &Rect<Au>) -> Au {
+    if wm.is_vertical() {
+        rect.size.width
+    } else {
+        rect.size.height
+    }
+}
 
 pub struct FloatFlowData {
     /// Data common to all flows.
@@ -27,18 +102,46 @@ pub struct FloatFlowData {
 
     containing_width: Au,
 
+    /// Left or right, as determined by the 'float' property.
+    float_type: FloatType,
+
+    /// Absolutely- and fixed-positioned descendants whose dimensions and
+    /// offsets are resolved lazily in `build_display_list_float`, because they
+    /// depend on the nearest positioned containing block rather than on normal
+    /// flow.
+    abs_descendants: ~[AbsDescendantInfo],
 
     /// Index into the box list for inline floats
     index: Option<uint>,
 
 }
 
+/// An out-of-flow (absolutely- or fixed-positioned) descendant of a float,
+/// recorded during height assignment and resolved at display-list time.
+pub struct AbsDescendantInfo {
+    /// The out-of-flow child flow.
+    flow: FlowContext,
+
+    /// The containing block rectangle: the float's padding box for `absolute`
+    /// descendants, or the viewport for `fixed` ones.
+    containing_block: Rect<Au>,
+
+    /// The position the child would have had in normal flow, used to resolve
+    /// `auto` offsets.
+    static_position: Point2D<Au>,
+
+    /// Whether this descendant is `position: fixed` (as opposed to `absolute`).
+    is_fixed: bool,
+}
+
 impl FloatFlowData {
     pub fn new(common: FlowData) -> FloatFlowData {
         FloatFlowData {
             common: common,
             containing_width: Au(0),
             box: None,
+            float_type: FloatLeft,
+            abs_descendants: ~[],
             index: None,
         }
     }
@@ -49,18 +152,19 @@ impl FloatFlowData {
             box.teardown();
         }
         self.box = None;
+        self.abs_descendants = ~[];
         self.index = None;
     }
 }
 
 impl FloatFlowData {
-    pub fn bubble_widths_float(@mut self, ctx: &LayoutContext) {
+    pub fn bubble_widths_float(&mut self, ctx: &LayoutContext) {
         let mut min_width = Au(0);
         let mut pref_width = Au(0);
 
         self.common.num_floats = 1;
 
-        for FloatFlow(self).each_child |child_ctx| {
+        for self.common.children.each |child_ctx| {
             //assert!(child_ctx.starts_block_flow() || child_ctx.starts_inline_flow());
 
             do child_ctx.with_mut_base |child_node| {
@@ -72,6 +176,7 @@ impl FloatFlowData {
 
         self.box.map(|&box| {
             let style = box.style();
+            self.float_type = style.float();
             do box.with_model |model| {
                 model.compute_borders(style)
             }
@@ -84,130 +189,285 @@ impl FloatFlowData {
         self.common.pref_width = pref_width;
     }
 
-    pub fn assign_widths_float(@mut self, _: &LayoutContext) { 
-        debug!("assign_widths_float: assigning width for flow %?",  self.common.id);
-        // position.size.width is set by parent even though we don't know
-        // position.origin yet.
-        let mut remaining_width = self.common.position.size.width;
-        self.containing_width = remaining_width;
-        let mut x_offset = Au(0);
+    pub fn assign_widths_float(&mut self, _: &LayoutContext) {
+        debug!("assign_widths_float: assigning inline-size for flow %?",  self.common.id);
+        // The inline-size of position.size is set by the parent even though we
+        // don't know the origin yet. Work in logical inline/block terms and map
+        // back to physical coordinates only when writing base.position.
+        // A float flow always carries a box; its writing mode governs the
+        // logical-to-physical mapping for this flow and its children.
+        let writing_mode = self.common.writing_mode;
+
+        let mut remaining_inline = inline_size(writing_mode, &self.common.position);
+        self.containing_width = remaining_inline;
+        let mut inline_offset = Au(0);
 
         for self.box.each |&box| {
             let style = box.style();
             do box.with_model |model| {
-                // Can compute padding here since we know containing block width.
-                model.compute_padding(style, remaining_width);
+                // Can compute padding here since we know the containing block
+                // inline-size.
+                model.compute_padding(style, remaining_inline);
 
-                // Margins for floats are 0 if auto.
-                let margin_top = MaybeAuto::from_margin(style.margin_top(),
-                                                        remaining_width).spec_or_default(Au(0));
-                let margin_bottom = MaybeAuto::from_margin(style.margin_bottom(),
-                                                           remaining_width).spec_or_default(Au(0));
-                let margin_left = MaybeAuto::from_margin(style.margin_left(),
-                                                        remaining_width).spec_or_default(Au(0));
-                let margin_right = MaybeAuto::from_margin(style.margin_right(),
-                                                           remaining_width).spec_or_default(Au(0));
+                // Margins for floats are 0 if auto. Resolve them in logical
+                // inline/block terms for the box's writing mode.
+                let margin_block_start = MaybeAuto::from_margin(style.margin_block_start(),
+                                                        remaining_inline).spec_or_default(Au(0));
+                let margin_block_end = MaybeAuto::from_margin(style.margin_block_end(),
+                                                           remaining_inline).spec_or_default(Au(0));
+                let margin_inline_start = MaybeAuto::from_margin(style.margin_inline_start(),
+                                                        remaining_inline).spec_or_default(Au(0));
+                let margin_inline_end = MaybeAuto::from_margin(style.margin_inline_end(),
+                                                           remaining_inline).spec_or_default(Au(0));
 
 
 
-                let shrink_to_fit = geometry::min(self.common.pref_width, 
-                                                  geometry::max(self.common.min_width, 
-                                                                remaining_width));
+                let shrink_to_fit = geometry::min(self.common.pref_width,
+                                                  geometry::max(self.common.min_width,
+                                                                remaining_inline));
 
 
-                let width = MaybeAuto::from_width(style.width(), 
-                                                  remaining_width).spec_or_default(shrink_to_fit);
-                debug!("assign_widths_float -- width: %?", width);
+                let inline_size = MaybeAuto::from_width(style.inline_size(),
+                                                  remaining_inline).spec_or_default(shrink_to_fit);
+                debug!("assign_widths_float -- inline-size: %?", inline_size);
 
-                model.margin.top = margin_top;
-                model.margin.right = margin_right;
-                model.margin.bottom = margin_bottom;
-                model.margin.left = margin_left;
+                model.margin.set_block_start(writing_mode, margin_block_start);
+                model.margin.set_inline_end(writing_mode, margin_inline_end);
+                model.margin.set_block_end(writing_mode, margin_block_end);
+                model.margin.set_inline_start(writing_mode, margin_inline_start);
 
-                x_offset = model.offset();
-                remaining_width = width;
+                inline_offset = model.offset();
+                remaining_inline = inline_size;
             }
 
+            let float_type = self.float_type;
             do box.with_mut_base |base| {
                 //The associated box is the border box of this flow
-                base.position.origin.x = base.model.margin.left;
-
-                let pb = base.model.padding.left + base.model.padding.right +
-                    base.model.border.left + base.model.border.right;
-                base.position.size.width = remaining_width + pb;
+                let inline_surround = base.model.padding.inline_start(writing_mode) +
+                    base.model.padding.inline_end(writing_mode) +
+                    base.model.border.inline_start(writing_mode) +
+                    base.model.border.inline_end(writing_mode);
+                let border_box_inline = remaining_inline + inline_surround;
+
+                // Left floats flow from the inline-start edge of the containing
+                // block; right floats flow from the inline-end edge, so their
+                // origin must be offset by the containing inline-size less the
+                // border box and the inline-end margin.
+                let inline_start_pos = match float_type {
+                    FloatLeft => base.model.margin.inline_start(writing_mode),
+                    FloatRight => self.containing_width -
+                        base.model.margin.inline_end(writing_mode) - border_box_inline,
+                };
+
+                // Map the logical inline axis back onto physical x/y. The block
+                // axis is written later by assign_height_float.
+                set_inline_position(writing_mode, &mut base.position, inline_start_pos);
+                set_inline_size(writing_mode, &mut base.position, border_box_inline);
             }
         }
 
-        self.common.position.size.width = remaining_width;
+        // Write the flow's own inline-size logically, so a vertical writing
+        // mode stores it in the physical height rather than the width.
+        set_inline_size(writing_mode, &mut self.common.position, remaining_inline);
 
-        for FloatFlow(self).each_child |kid| {
+        let has_inorder_children = self.common.is_inorder || self.common.num_floats > 0;
+
+        for self.common.children.each |kid| {
             //assert!(kid.starts_block_flow() || kid.starts_inline_flow());
 
             do kid.with_mut_base |child_node| {
-                child_node.position.origin.x = x_offset;
-                child_node.position.size.width = remaining_width;
+                // Absolutely- and fixed-positioned children are not sized or
+                // placed by normal flow; their dimensions are resolved at
+                // display-list time against their containing block.
+                if !child_node.is_absolutely_positioned() {
+                    set_inline_position(writing_mode, &mut child_node.position, inline_offset);
+                    set_inline_size(writing_mode, &mut child_node.position, remaining_inline);
+
+                    child_node.is_inorder = has_inorder_children;
+                    if !child_node.is_inorder {
+                        // This subtree has no floats, so it has no cross-sibling
+                        // dependency and its floats_in can be a fresh empty
+                        // context.
+                        child_node.floats_in = Invalid;
+                    }
+                }
             }
         }
     }
 
-    pub fn assign_height_float(@mut self, ctx: &mut LayoutContext) {
-        for FloatFlow(self).each_child |kid| {
-            kid.assign_height(ctx);
+    pub fn assign_height_float(&mut self, ctx: &mut LayoutContext) {
+        for self.common.children.each |kid| {
+            // Absolutely- and fixed-positioned children are deferred to
+            // display-list time; running normal-flow height assignment on them
+            // here would size them against the inline-size that
+            // assign_widths_float deliberately skipped.
+            let mut in_flow = true;
+            do kid.with_base |child_node| {
+                in_flow = !child_node.is_absolutely_positioned();
+            }
+            if in_flow {
+                kid.assign_height(ctx);
+            }
         }
 
-        let mut cur_y = Au(0);
-        let mut top_offset = Au(0);
+        self.abs_descendants = ~[];
+
+        let writing_mode = self.common.writing_mode;
+
+        let mut cur_block = Au(0);
+        let mut block_start_offset = Au(0);
+        // Inline-direction offset of the content box, i.e. where an in-flow
+        // child's inline-start edge sits. Mirrors `inline_offset` in
+        // `assign_widths_float`.
+        let mut content_inline_offset = Au(0);
 
         for self.box.each |&box| {
             do box.with_model |model| {
-                top_offset = model.margin.top + model.border.top + model.padding.top;
-                cur_y += top_offset;
+                block_start_offset = model.margin.block_start(writing_mode) +
+                    model.border.block_start(writing_mode) +
+                    model.padding.block_start(writing_mode);
+                cur_block += block_start_offset;
+                content_inline_offset = model.offset();
             }
         }
 
-        for FloatFlow(self).each_child |kid| {
+        // Static positions of out-of-flow children: the in-flow spot each would
+        // have occupied, recorded as the block progression reaches it.
+        let mut out_of_flow = ~[];
+
+        // A float always establishes a new float context (`num_floats` is set
+        // to 1 in `bubble_widths_float`), so `has_inorder_children` is always
+        // true in `assign_widths_float`: every in-flow child is marked
+        // `is_inorder`, none has its `floats_in` reset to `Invalid`, and all
+        // direct children are threaded serially here.
+        for self.common.children.each |kid| {
+            let mut static_record = None;
             do kid.with_mut_base |child_node| {
-                child_node.position.origin.y = cur_y;
-                cur_y += child_node.position.size.height;
+                // Out-of-flow children take no space in the block progression,
+                // but we still record the static position they would have had
+                // in normal flow at this point in the block direction.
+                if child_node.is_absolutely_positioned() {
+                    let mut static_rect = Rect(Point2D(Au(0), Au(0)), Size2D(Au(0), Au(0)));
+                    set_inline_position(writing_mode, &mut static_rect, content_inline_offset);
+                    set_block_position(writing_mode, &mut static_rect, cur_block);
+                    static_record = Some((child_node.is_fixed(), static_rect.origin));
+                } else {
+                    set_block_position(writing_mode, &mut child_node.position, cur_block);
+                    cur_block += block_size(writing_mode, &child_node.position);
+                }
+            }
+            for static_record.each |&(is_fixed, static_position)| {
+                out_of_flow.push((kid, is_fixed, static_position));
             }
         }
 
-        let mut height = cur_y - top_offset;
-        
-        let mut noncontent_height = Au(0);
+        let mut content_block = cur_block - block_start_offset;
+
+        // Block-direction border + padding (the border box surround) and the
+        // block margins are tracked separately: the border box must not include
+        // margin, but the area reserved in the `FloatContext` must.
+        let mut border_padding_block = Au(0);
+        let mut margin_block = Au(0);
         self.box.map(|&box| {
             do box.with_mut_base |base| {
                 //The associated box is the border box of this flow
-                base.position.origin.y = base.model.margin.top;
-
-                noncontent_height = base.model.padding.top + base.model.padding.bottom +
-                    base.model.border.top + base.model.border.bottom;
-                base.position.size.height = height + noncontent_height;
-
-                noncontent_height += base.model.margin.top + base.model.margin.bottom;
+                set_block_position(writing_mode, &mut base.position,
+                                   base.model.margin.block_start(writing_mode));
+
+                border_padding_block = base.model.padding.block_start(writing_mode) +
+                    base.model.padding.block_end(writing_mode) +
+                    base.model.border.block_start(writing_mode) +
+                    base.model.border.block_end(writing_mode);
+                set_block_size(writing_mode, &mut base.position,
+                               content_block + border_padding_block);
+
+                margin_block = base.model.margin.block_start(writing_mode) +
+                    base.model.margin.block_end(writing_mode);
             }
         });
 
-        
-        //TODO(eatkinson): compute heights properly using the 'height' property.
+
+        // Resolve the 'block-size' property, falling back to the summed content
+        // block-size when it is auto, then clamp to 'min-block-size'/
+        // 'max-block-size'. Floats are not handed a definite containing-block
+        // block-size here, so percentages resolve to `auto` (CSS 10.5) while
+        // definite lengths still apply; 'max' of none is treated as unbounded.
+        // Per CSS, the max clamp is applied first and the min clamp last so that
+        // min-block-size wins when max < min.
+        let small = Au(0);
+        let large = Au(0x4000_0000);
         for self.box.each |&box| {
+            let style = box.style();
 
-            let height_prop = 
-                MaybeAuto::from_height(box.style().height(), Au(0)).spec_or_default(Au(0));
+            content_block = match block_prop_or_auto(
+                    MaybeAuto::from_height(style.block_size(), small),
+                    MaybeAuto::from_height(style.block_size(), large)) {
+                Auto => content_block,
+                Specified(h) => h,
+            };
+
+            let min_block = block_prop_or_auto(
+                    MaybeAuto::from_height(style.min_block_size(), small),
+                    MaybeAuto::from_height(style.min_block_size(), large))
+                .spec_or_default(Au(0));
+            let max_block = match block_prop_or_auto(
+                    MaybeAuto::from_height(style.max_block_size(), small),
+                    MaybeAuto::from_height(style.max_block_size(), large)) {
+                Auto => None,
+                Specified(h) => Some(h),
+            };
+
+            let mut clamped = content_block;
+            for max_block.each |&mb| {
+                clamped = geometry::min(clamped, mb);
+            }
+            clamped = geometry::max(min_block, clamped);
+
+            content_block = clamped;
+            debug!("assign_height_float -- content block-size: %?", content_block);
+            do box.with_mut_base |base| {
+                set_block_size(writing_mode, &mut base.position,
+                               content_block + border_padding_block);
+            }
+        }
+
+        // The area reserved in the float context is the margin box.
+        let block_size_val = content_block + border_padding_block + margin_block;
 
-            height = geometry::max(height, height_prop) + noncontent_height;
-            debug!("assign_height_float -- height: %?", height);
+        // Record out-of-flow descendants with the containing block they will be
+        // resolved against at display-list time: the float's padding box for
+        // `absolute`, or the viewport for `fixed`. Their static position is the
+        // spot they would have occupied in normal flow.
+        let mut padding_box = Rect(Point2D(Au(0), Au(0)), Size2D(Au(0), Au(0)));
+        for self.box.each |&box| {
             do box.with_mut_base |base| {
-                base.position.size.height = height;
+                padding_box = Rect(Point2D(base.position.origin.x + base.model.border.left,
+                                           base.position.origin.y + base.model.border.top),
+                                   Size2D(base.position.size.width - base.model.border.left -
+                                              base.model.border.right,
+                                          base.position.size.height - base.model.border.top -
+                                              base.model.border.bottom));
             }
         }
+        let viewport = Rect(Point2D(Au(0), Au(0)), ctx.screen_size.size);
+
+        for out_of_flow.each |&(kid, is_fixed, static_position)| {
+            self.abs_descendants.push(AbsDescendantInfo {
+                flow: kid,
+                containing_block: if is_fixed { viewport } else { padding_box },
+                static_position: static_position,
+                is_fixed: is_fixed,
+            });
+        }
 
+        // `PlacementInfo` is expressed in logical terms: `width` is the float's
+        // inline extent, `height` its block (margin-box) extent.
         let info = PlacementInfo {
-            width: self.common.position.size.width,
-            height: height,
+            width: inline_size(writing_mode, &self.common.position),
+            height: block_size_val,
             ceiling: Au(0),
             max_width: self.containing_width,
-            f_type: FloatLeft,
+            f_type: self.float_type,
         };
 
         self.common.floats_out = self.common.floats_in.add_float(&info);
@@ -216,7 +476,7 @@ impl FloatFlowData {
 
     }
 
-    pub fn build_display_list_float<E:ExtraDisplayListData>(@mut self,
+    pub fn build_display_list_float<E:ExtraDisplayListData>(&mut self,
                                                             builder: &DisplayListBuilder,
                                                             dirty: &Rect<Au>, 
                                                             offset: &Point2D<Au>,
@@ -226,10 +486,33 @@ impl FloatFlowData {
         });
 
 
-        // go deeper into the flow tree
-        let flow = FloatFlow(self);
-        for flow.each_child |child| {
-            flow.build_display_list_for_child(builder, child, dirty, offset, list)
+        // Go deeper into the flow tree. The flow now owns its children
+        // directly, so we iterate them in place rather than through a managed
+        // pointer.
+        for self.common.children.each |child| {
+            // Out-of-flow children are handled below, once their dimensions
+            // have been resolved against the stored containing block.
+            let mut in_flow = true;
+            do child.with_base |child_node| {
+                in_flow = !child_node.is_absolutely_positioned();
+            }
+            if in_flow {
+                self.build_display_list_for_child(builder, child, dirty, offset, list)
+            }
+        }
+
+        // Resolve the final dimensions and offsets of absolutely- and
+        // fixed-positioned descendants now that their containing block is
+        // known, then recurse into them. `fixed` descendants are positioned
+        // relative to the viewport, so they ignore the accumulated `offset`.
+        for self.abs_descendants.each |desc| {
+            desc.flow.resolve_absolute_position(desc.containing_block, desc.static_position);
+            let child_offset = if desc.is_fixed {
+                desc.containing_block.origin
+            } else {
+                *offset
+            };
+            self.build_display_list_for_child(builder, desc.flow, dirty, &child_offset, list)
         }
     }
 }